@@ -1,46 +1,242 @@
+use std::fs;
+use std::io;
+use std::env;
 use std::thread;
-use std::sync::Arc;
-use std::path::PathBuf;
-use std::time::Duration;
-use std::collections::HashMap;
-use std::sync::mpsc::{Sender, Receiver, channel};
+use std::sync::{Arc, Mutex, Condvar};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::path::{Path, PathBuf};
+use std::thread::JoinHandle;
+use std::ops::ControlFlow;
+use std::time::{Duration, Instant, SystemTime};
+use std::mem;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::hash_map::Entry;
+use std::sync::mpsc::{Sender, Receiver, channel, SendError, TryRecvError, RecvTimeoutError};
 
 use notify::{Watcher, RecursiveMode, DebouncedEvent, RecommendedWatcher, watcher};
-use npnc::{ProduceError, ConsumeError};
-use npnc::bounded::spsc::{channel as npnc_channel, Producer, Consumer};
 
 use pi_atom::Atom;
 
+/*
+* 等待文件事件的超时时长，单位毫秒，超时后检查管理事件
+*/
+const WAIT_EVENT_TIMEOUT: u64 = 100;
+
 /*
 * 监听选项
+* 缓冲时间即防抖时间，单位毫秒，同一路径在缓冲时间内的连续事件会合并为一次通知，
+* 并在该路径最后一次事件后的缓冲时间结束时通知，合并后的事件类型不是最后一次事件的类型，而是这些事件的最终效果，
+* 例如多次修改为一次修改，创建后修改为创建，修改后移除为移除，创建后移除则不通知
+* 监听目录时的是否递归遍历，为true则监听目录及其所有子目录，为false则只监听目录下的直接子项，
+* 子目录中的文件和目录的事件不会通知监听者
+* 监听路径重叠时，每个文件事件只通知最具体的监听路径，即监听该文件的路径，或包含该路径的最长的监听目录，
+* 同一个目录只实际监听一次，使用最先监听该目录的监听路径的缓冲时间
 */
 #[derive(Debug, Clone)]
 pub enum FSMonitorOptions {
-    File(Atom, u64),               //监听单个文件，路径和缓冲时间
+    File(Atom, u64),               //监听单个文件，路径和缓冲时间，文件被替换或移除后重建仍然继续监听
     Files(Vec<(Atom, u64)>),       //监听多个文件，路径和缓冲时间的列表
     Dir(Atom, bool, u64),          //监听单个目录，路径、是否递归遍历和缓冲时间
     Dirs(Vec<(Atom, bool, u64)>),  //监听多个目录，路径、是否递归遍历和缓冲时间的列表
 }
 
+/*
+* 文件改变事件类型
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FSEventKind {
+    Created,                                //已创建
+    Modified,                               //已修改
+    Removed,                                //已移除
+    Renamed { from: PathBuf, to: PathBuf }, //已改名，源路径和目标路径
+}
+
 /*
 * 文件改变事件
 */
-#[derive(Debug, Clone)]
-pub enum FSChangeEvent {
-    Create(PathBuf),
-    Write(PathBuf),
-    Remove(PathBuf),
-    Rename(PathBuf, PathBuf),
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FSEvent {
+    pub root: PathBuf,      //产生事件的监听路径，与增加监听时的路径一致
+    pub path: PathBuf,      //受影响的路径，改名时为目标路径
+    pub kind: FSEventKind,  //事件类型
+    pub time: SystemTime,   //发生事件的时间，缓冲时间内合并的事件为最后一次事件的时间
+}
+
+impl FSEvent {
+    //构建一个文件改变事件
+    pub fn new(root: PathBuf, path: PathBuf, kind: FSEventKind, time: SystemTime) -> Self {
+        FSEvent {
+            root,
+            path,
+            kind,
+            time,
+        }
+    }
 }
 
 /*
 * 监听者
 */
 #[derive(Clone)]
-pub struct FSListener(pub Arc<dyn Fn(FSChangeEvent)>);
+pub struct FSListener(pub Arc<dyn Fn(FSEvent)>);
 
 unsafe impl Send for FSListener {}
 
+/*
+* 可控制的监听者，返回ControlFlow::Break表示不再通知事件，并关闭监听器
+*/
+#[derive(Clone)]
+pub struct FSListenerControl(pub Arc<dyn Fn(FSEvent) -> ControlFlow<()> + Send + Sync>);
+
+/*
+* 监听器的监听者
+*/
+#[derive(Clone)]
+enum MonitorListener {
+    Listener(FSListener),           //监听者
+    Control(FSListenerControl),     //可控制的监听者
+}
+
+impl MonitorListener {
+    //通知监听者，返回ControlFlow::Break表示不再通知事件
+    fn notify(&self, event: FSEvent) -> ControlFlow<()> {
+        match self {
+            MonitorListener::Listener(listener) => {
+                (listener.0)(event);
+                ControlFlow::Continue(())
+            },
+            MonitorListener::Control(listener) => (listener.0)(event),
+        }
+    }
+}
+
+/*
+* 路径过滤器，返回true表示需要通知监听者
+*/
+#[derive(Clone)]
+pub struct FSFilter(pub Arc<dyn Fn(&Path) -> bool + Send + Sync>);
+
+impl FSFilter {
+    //构建一个只允许指定扩展名的路径过滤器，扩展名不包括"."，且忽略大小写
+    pub fn with_extensions(extensions: &[&str]) -> Self {
+        let extensions: Vec<String> = extensions.iter().map(|ext| ext.to_string()).collect();
+        FSFilter(Arc::new(move |path: &Path| {
+            match path.extension().and_then(|ext| ext.to_str()) {
+                None => false,
+                Some(ext) => extensions.iter().any(|e| e.eq_ignore_ascii_case(ext)),
+            }
+        }))
+    }
+
+    //判断指定事件是否需要通知监听者，改名事件的源路径或目标路径满足过滤条件即可
+    pub fn is_match(&self, event: &FSEvent) -> bool {
+        match &event.kind {
+            FSEventKind::Renamed { from, to } => (self.0)(from) || (self.0)(to),
+            _ => (self.0)(&event.path),
+        }
+    }
+}
+
+/*
+* 事件队列已满时的处理策略
+* 阻塞只是让接收线程等待监听者，不会限制内存，因为阻塞期间底层监听器产生的文件事件仍然会在无界的通道中累积，
+* 需要限制内存时应该使用丢弃策略
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FSQueuePolicy {
+    DropOldest, //丢弃队列中最早的事件，再加入新事件
+    DropNewest, //丢弃新事件
+    Block,      //阻塞接收事件，直到监听者处理事件后队列有空位，不丢弃事件，但不限制未处理事件占用的内存
+}
+
+/*
+* 有界事件队列，接收线程加入事件，通知线程取出事件并通知监听者
+*/
+struct FSEventQueue {
+    capacity: usize,                        //队列容量
+    policy: FSQueuePolicy,                  //队列已满时的处理策略
+    events: Mutex<(VecDeque<FSEvent>, bool)>, //事件列表和是否已关闭
+    not_empty: Condvar,                     //队列非空的条件变量
+    not_full: Condvar,                      //队列未满的条件变量
+    dropped: Arc<AtomicUsize>,              //已丢弃的事件数量
+}
+
+impl FSEventQueue {
+    //构建一个有界事件队列
+    fn new(capacity: usize, policy: FSQueuePolicy, dropped: Arc<AtomicUsize>) -> Self {
+        FSEventQueue {
+            capacity,
+            policy,
+            events: Mutex::new((VecDeque::new(), false)),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+            dropped,
+        }
+    }
+
+    //加入事件，队列已满时按处理策略处理，队列已关闭则返回false
+    fn push(&self, event: FSEvent) -> bool {
+        let mut events = self.events.lock().unwrap_or_else(|e| e.into_inner());
+        loop {
+            if events.1 {
+                return false;
+            }
+
+            if events.0.len() < self.capacity {
+                events.0.push_back(event);
+                self.not_empty.notify_one();
+                return true;
+            }
+
+            match self.policy {
+                FSQueuePolicy::DropOldest => {
+                    events.0.pop_front();
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                },
+                FSQueuePolicy::DropNewest => {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    return true;
+                },
+                FSQueuePolicy::Block => {
+                    events = self.not_full.wait(events).unwrap_or_else(|e| e.into_inner());
+                },
+            }
+        }
+    }
+
+    //取出事件，超时或队列已关闭则返回None
+    fn pop(&self, timeout: Duration) -> Option<FSEvent> {
+        let mut events = self.events.lock().unwrap_or_else(|e| e.into_inner());
+        if !events.1 && events.0.is_empty() {
+            events = self.not_empty.wait_timeout(events, timeout).unwrap_or_else(|e| e.into_inner()).0;
+        }
+
+        if events.1 {
+            return None;
+        }
+
+        let event = events.0.pop_front();
+        if event.is_some() {
+            self.not_full.notify_one();
+        }
+        event
+    }
+
+    //关闭队列，并唤醒所有等待的线程
+    fn close(&self) {
+        let mut events = self.events.lock().unwrap_or_else(|e| e.into_inner());
+        events.1 = true;
+        events.0.clear();
+        self.not_empty.notify_all();
+        self.not_full.notify_all();
+    }
+
+    //判断队列是否已关闭
+    fn is_closed(&self) -> bool {
+        self.events.lock().unwrap_or_else(|e| e.into_inner()).1
+    }
+}
+
 /*
 * 监听器管理事件
 */
@@ -50,84 +246,512 @@ enum FSMonitorEvent {
     Pause(usize),   //暂停监听器
 }
 
+/*
+* 监听路径
+*/
+struct WatchRoot {
+    path: PathBuf,              //文件事件的路径，监听文件时为文件的实际路径，监听目录时为目录的绝对路径
+    dir: PathBuf,               //实际监听的路径，监听文件时为文件所在的目录
+    file: Option<bool>,         //监听文件时文件是否存在
+    is_rec: bool,               //监听目录时是否递归遍历
+    time: u64,                  //缓冲时间，单位毫秒
+    dirs: HashSet<PathBuf>,     //该监听路径实际非递归监听的所有目录
+}
+
+impl WatchRoot {
+    //判断指定路径的文件事件是否属于该监听路径，监听文件时只有该文件，非递归监听目录时只有目录本身和直接子项
+    fn covers(&self, path: &Path) -> bool {
+        match self.file {
+            Some(_) => path == self.path,
+            None => path.starts_with(&self.path) && (self.is_rec || path == self.path || path.parent() == Some(self.path.as_path())),
+        }
+    }
+}
+
+/*
+* 监听表，所有监听器共用一个事件发送器，接收线程阻塞的接收所有监听器的事件，并按事件的路径找到所属的监听路径，
+* 所有目录都非递归的监听，同一个目录只实际监听一次，由需要该目录的所有监听路径共享，并使用最先监听该目录的监听路径的缓冲时间
+*/
+struct Watches {
+    sender: Option<Sender<DebouncedEvent>>,             //所有监听器共用的事件发送器，只在运行时存在
+    watchers: HashMap<u64, RecommendedWatcher>,         //每个缓冲时间的监听器
+    dirs: HashMap<PathBuf, (u64, usize)>,               //实际监听的所有目录，及其监听器的缓冲时间和引用计数
+    roots: HashMap<PathBuf, WatchRoot>,                 //监听路径表，关键字与增加监听时的路径一致
+    moved: HashSet<PathBuf>,                            //已改名的目录，底层监听器会在改名事件后再通知一次该目录自身的移除，需要忽略
+    existing: Vec<(PathBuf, PathBuf, Option<bool>)>,    //等待通知已存在的文件和目录的监听路径、实际路径和是否递归遍历，监听文件时为None
+}
+
+impl Watches {
+    //构建一个空的监听表
+    fn new() -> Self {
+        Watches {
+            sender: None,
+            watchers: HashMap::new(),
+            dirs: HashMap::new(),
+            roots: HashMap::new(),
+            moved: HashSet::new(),
+            existing: Vec::new(),
+        }
+    }
+
+    //增加监听路径，并监听其实际监听的路径，失败则移除该监听路径
+    fn add_root(&mut self, key: &Path, root: WatchRoot, emit_existing: bool) -> Result<(), String> {
+        if self.roots.contains_key(key) {
+            //指定路径已监听
+            return Err(format!("add fs monitor failed, path exists"));
+        }
+
+        let dir = root.dir.clone();
+        if emit_existing {
+            let is_rec = if root.file.is_some() {
+                None
+            } else {
+                Some(root.is_rec)
+            };
+            self.existing.push((key.to_path_buf(), root.path.clone(), is_rec));
+        }
+        self.roots.insert(key.to_path_buf(), root);
+        if let Err(e) = self.watch_dir(key, &dir) {
+            let _ = self.remove_root(key);
+            return Err(format!("add fs monitor failed, path: {:?}, e: {:?}", key, e));
+        }
+        Ok(())
+    }
+
+    //为指定监听路径非递归的监听指定目录，目录已被其它监听路径监听则只增加引用计数，
+    //未运行、监听路径已移除或该监听路径已监听该目录则返回false
+    fn watch_dir(&mut self, key: &Path, dir: &Path) -> notify::Result<bool> {
+        let time = match self.roots.get(key) {
+            Some(root) if !root.dirs.contains(dir) => root.time,
+            _ => return Ok(false),
+        };
+
+        match self.dirs.get_mut(dir) {
+            Some((_, count)) => *count += 1,
+            None => {
+                let sender = match &self.sender {
+                    None => return Ok(false),
+                    Some(sender) => sender,
+                };
+                let watcher = match self.watchers.entry(time) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => entry.insert(watcher(sender.clone(), Duration::from_millis(time))?),
+                };
+                watcher.watch(dir, RecursiveMode::NonRecursive)?;
+                self.dirs.insert(dir.to_path_buf(), (time, 1));
+            },
+        }
+        if let Some(root) = self.roots.get_mut(key) {
+            root.dirs.insert(dir.to_path_buf());
+        }
+        Ok(true)
+    }
+
+    //释放指定目录的一次引用，没有引用时移除该目录的监听，并释放没有监听目录的监听器
+    fn release_dir(&mut self, dir: &Path) -> notify::Result<()> {
+        let time = match self.dirs.get_mut(dir) {
+            None => return Ok(()),
+            Some((time, count)) => {
+                *count -= 1;
+                if *count > 0 {
+                    return Ok(());
+                }
+                *time
+            },
+        };
+
+        self.dirs.remove(dir);
+        let result = match self.watchers.get_mut(&time) {
+            None => Ok(()),
+            Some(watcher) => watcher.unwatch(dir),
+        };
+        if !self.dirs.values().any(|(other, _)| *other == time) {
+            self.watchers.remove(&time);
+        }
+        result
+    }
+
+    //释放指定监听路径对指定目录及其所有子目录的监听，已移除的目录的监听已自动移除，因此忽略移除监听的错误，
+    //返回该监听路径是否已监听指定目录
+    fn unwatch_dirs(&mut self, key: &Path, dir: &Path) -> bool {
+        let removed: Vec<PathBuf> = match self.roots.get_mut(key) {
+            None => return false,
+            Some(root) => {
+                let removed: Vec<PathBuf> = root.dirs.iter().filter(|path| path.starts_with(dir)).cloned().collect();
+                for path in &removed {
+                    root.dirs.remove(path);
+                }
+                removed
+            },
+        };
+
+        for path in &removed {
+            let _ = self.release_dir(path);
+        }
+        removed.iter().any(|path| path == dir)
+    }
+
+    //移除指定监听路径，并释放其监听的所有目录，不再通知其已存在的文件和目录，子目录可能已被移除，因此只返回移除实际监听的路径的结果
+    fn remove_root(&mut self, key: &Path) -> notify::Result<()> {
+        let root = match self.roots.remove(key) {
+            None => return Ok(()),
+            Some(root) => root,
+        };
+        self.existing.retain(|(other, _, _)| other != key);
+
+        let mut result = Ok(());
+        for dir in &root.dirs {
+            let r = self.release_dir(dir);
+            if dir == &root.dir {
+                result = r;
+            }
+        }
+        result
+    }
+
+    //移除所有监听路径，并释放共用的事件发送器和所有监听器
+    fn clear(&mut self) -> Result<(), String> {
+        let mut result = Ok(());
+        let keys: Vec<PathBuf> = self.roots.keys().cloned().collect();
+        for key in keys {
+            if let Err(e) = self.remove_root(&key) {
+                if result.is_ok() {
+                    result = Err(format!("stop fs monitor failed, path: {:?}, e: {:?}", key, e));
+                }
+            }
+        }
+
+        self.sender = None;
+        self.watchers.clear();
+        self.dirs.clear();
+        self.moved.clear();
+        self.existing.clear();
+        result
+    }
+
+    //获取指定路径的文件事件所属的监听路径，优先为监听该文件的监听路径，其次为包含该路径的最长的监听目录
+    fn route(&self, path: &Path) -> Option<PathBuf> {
+        let mut found: Option<(&PathBuf, usize)> = None;
+        for (key, root) in &self.roots {
+            if !root.covers(path) {
+                continue;
+            }
+
+            if root.file.is_some() {
+                return Some(key.clone());
+            }
+            let len = root.path.components().count();
+            match found {
+                Some((_, other)) if other >= len => (),
+                _ => found = Some((key, len)),
+            }
+        }
+        found.map(|(key, _)| key.clone())
+    }
+
+    //获取指定路径的文件事件的缓冲时间，即实际监听该路径所在目录的监听器的缓冲时间
+    fn buffer_time(&self, key: &Path, path: &Path) -> u64 {
+        path.parent()
+            .and_then(|dir| self.dirs.get(dir))
+            .or_else(|| self.dirs.get(path))
+            .map(|(time, _)| *time)
+            .or_else(|| self.roots.get(key).map(|root| root.time))
+            .unwrap_or(0)
+    }
+
+    //将底层监听器的事件转换为所属监听路径的文件事件，改名的源路径和目标路径属于不同的监听路径时，
+    //分别作为源路径的移除事件和目标路径的创建事件，并返回需要监听的目录的监听路径、实际路径和是否通知其中已存在的文件和目录
+    fn recv(&mut self, event: DebouncedEvent, events: &mut Vec<FSEvent>, walks: &mut Vec<(PathBuf, PathBuf, bool)>) {
+        match event {
+            DebouncedEvent::Create(path) => {
+                self.moved.remove(&path);
+                if let Some(key) = self.route(&path) {
+                    self.push(&key, path, FSEventKind::Created, events, walks);
+                }
+            },
+            DebouncedEvent::Write(path) => {
+                if let Some(key) = self.route(&path) {
+                    self.push(&key, path, FSEventKind::Modified, events, walks);
+                }
+            },
+            DebouncedEvent::Remove(path) => {
+                if self.moved.remove(&path) {
+                    //已改名的目录自身的移除
+                    return;
+                }
+                if let Some(key) = self.route(&path) {
+                    self.push(&key, path, FSEventKind::Removed, events, walks);
+                }
+            },
+            DebouncedEvent::Rename(from, to) => {
+                if self.dirs.contains_key(&from) {
+                    self.moved.insert(from.clone());
+                }
+                match (self.route(&from), self.route(&to)) {
+                    (Some(from_key), Some(to_key)) if from_key == to_key && self.roots[&from_key].file.is_none() => {
+                        self.push(&to_key, to.clone(), FSEventKind::Renamed { from, to }, events, walks);
+                    },
+                    (from_key, to_key) => {
+                        if let Some(key) = from_key {
+                            self.push(&key, from, FSEventKind::Removed, events, walks);
+                        }
+                        if let Some(key) = to_key {
+                            self.push(&key, to, FSEventKind::Created, events, walks);
+                        }
+                    },
+                }
+            },
+            _ => (),
+        }
+    }
+
+    //加入指定监听路径的文件事件，监听文件时文件存在时被替换或重建视为修改，
+    //递归监听目录时，需要监听新建或移入的目录，并释放已移除或移走的目录的监听
+    fn push(&mut self, key: &Path, path: PathBuf, kind: FSEventKind, events: &mut Vec<FSEvent>, walks: &mut Vec<(PathBuf, PathBuf, bool)>) {
+        let time = self.buffer_time(key, &path);
+        let root = match self.roots.get_mut(key) {
+            None => return,
+            Some(root) => root,
+        };
+
+        let kind = match (&mut root.file, kind) {
+            (Some(is_exists), FSEventKind::Created) => {
+                if *is_exists {
+                    FSEventKind::Modified
+                } else {
+                    *is_exists = true;
+                    FSEventKind::Created
+                }
+            },
+            (Some(is_exists), FSEventKind::Removed) => {
+                *is_exists = false;
+                FSEventKind::Removed
+            },
+            (_, kind) => kind,
+        };
+        let is_rec = root.file.is_none() && root.is_rec;
+        match &kind {
+            FSEventKind::Created if is_rec => {
+                //只有新建的目录需要通知开始监听前已在其中创建的文件和目录，改名的目录中的文件和目录不变
+                walks.push((key.to_path_buf(), path.clone(), true));
+            },
+            FSEventKind::Removed => {
+                self.unwatch_dirs(key, &path);
+            },
+            FSEventKind::Renamed { from, to } => {
+                self.unwatch_dirs(key, from);
+                if is_rec {
+                    walks.push((key.to_path_buf(), to.clone(), false));
+                }
+            },
+            _ => (),
+        }
+        events.push(FSEvent::new(key.to_path_buf(), path, kind, event_time(time)));
+    }
+}
+
 /*
 * 文件系统监听器
 */
 pub struct FSMonitor {
-    is_running: bool,                                   //是否正在运行
+    is_running: Arc<AtomicBool>,                        //是否正在运行，监听者请求关闭后由通知线程设置为false
     options: FSMonitorOptions,                          //初始化选项
-    watchers: HashMap<PathBuf, RecommendedWatcher>,     //监听器表
-    listener: FSListener,                               //监听者
-    watcher_sender: Option<Sender<DebouncedEvent>>,     //监听器消息发送器
-    manager_sender: Option<Producer<FSMonitorEvent>>,   //管理消息发送器
+    watches: Arc<Mutex<Watches>>,                       //监听表，由接收线程阻塞的接收所有监听器的事件
+    listener: MonitorListener,                          //监听者
+    filter: Option<FSFilter>,                           //路径过滤器
+    emit_existing: bool,                                //运行时是否通知已存在的文件和目录
+    follow_symlinks: bool,                              //递归监听目录时是否跟随符号链接的目录
+    rename_window: u64,                                 //合并移除和创建为改名的时长，单位毫秒，为0则不合并
+    queue_capacity: usize,                              //事件队列容量
+    queue_policy: FSQueuePolicy,                        //事件队列已满时的处理策略
+    dropped: Arc<AtomicUsize>,                          //已丢弃的事件数量
+    queue: Option<Arc<FSEventQueue>>,                   //事件队列
+    manager_sender: Option<Sender<FSMonitorEvent>>,     //管理消息发送器
+    handles: Vec<JoinHandle<()>>,                       //接收线程和通知线程的句柄
 }
 
 impl Drop for FSMonitor {
     fn drop(&mut self) {
-        //关闭监听器
+        //关闭监听器，并移除所有路径的监听
         if let Err(e) = self.stop() {
             println!("!!!> Drop FSMonitor Error, e: {:?}", e);
         }
-
-        //移除所有路径的监听
-        for (path, mut watcher) in self.watchers.drain() {
-            if let Err(e) = watcher.unwatch(path) {
-                println!("!!!> Drop FSMonitor Error, e: {:?}", e);
-            }
-        }
     }
 }
 
 impl FSMonitor {
     //构建一个文件系统监听器
     pub fn new(options: FSMonitorOptions, listener: FSListener) -> Self {
+        FSMonitor::with_listener(options, MonitorListener::Listener(listener))
+    }
+
+    //构建一个使用可控制的监听者的文件系统监听器，监听者返回ControlFlow::Break后不再通知事件，
+    //并关闭接收线程和通知线程，移除所有路径的监听，之后is_running返回false，可以再次运行
+    pub fn with_control(options: FSMonitorOptions, listener: FSListenerControl) -> Self {
+        FSMonitor::with_listener(options, MonitorListener::Control(listener))
+    }
+
+    //构建一个使用指定监听者的文件系统监听器
+    fn with_listener(options: FSMonitorOptions, listener: MonitorListener) -> Self {
         FSMonitor {
-            is_running: false,
+            is_running: Arc::new(AtomicBool::new(false)),
             options: options,
-            watchers: HashMap::new(),
+            watches: Arc::new(Mutex::new(Watches::new())),
             listener: listener,
-            watcher_sender: None,
+            filter: None,
+            emit_existing: false,
+            follow_symlinks: false,
+            rename_window: 0,
+            queue_capacity: usize::MAX,
+            queue_policy: FSQueuePolicy::Block,
+            dropped: Arc::new(AtomicUsize::new(0)),
+            queue: None,
             manager_sender: None,
+            handles: Vec::new(),
+        }
+    }
+
+    //设置路径过滤器，只允许在运行前设置
+    pub fn set_filter(&mut self, filter: FSFilter) -> Result<(), String> {
+        if self.is_running() {
+            return Err(format!("set fs monitor filter failed, already running"));
+        }
+
+        self.filter = Some(filter);
+        Ok(())
+    }
+
+    //设置运行时是否将监听路径下已存在的文件和目录作为创建事件通知监听者，只允许在运行前设置，包括运行时增加的监听路径，
+    //已存在的文件和目录会在开始监听后、通知其它事件前通知，因此开始监听期间创建的文件和目录可能会通知两次
+    pub fn set_emit_existing(&mut self, emit_existing: bool) -> Result<(), String> {
+        if self.is_running() {
+            return Err(format!("set fs monitor emit existing failed, already running"));
+        }
+
+        self.emit_existing = emit_existing;
+        Ok(())
+    }
+
+    //设置递归监听目录时是否跟随符号链接的目录，默认不跟随，只允许在运行前设置，
+    //不跟随时，非递归的监听目录下的每个实际目录，不进入符号链接的目录，符号链接本身的事件仍然通知，
+    //新建或从监听路径外移入的目录在收到创建事件后才开始监听，因此在此之前已在其中的文件和目录，会在该目录的创建事件后作为创建事件通知，
+    //跟随时，初始遍历不会重复进入同一个目录，以避免符号链接的循环
+    pub fn set_follow_symlinks(&mut self, follow_symlinks: bool) -> Result<(), String> {
+        if self.is_running() {
+            return Err(format!("set fs monitor follow symlinks failed, already running"));
         }
+
+        self.follow_symlinks = follow_symlinks;
+        Ok(())
+    }
+
+    //设置合并移除和创建为改名的时长，单位毫秒，为0则不合并，只允许在运行前设置，
+    //移除和创建事件会延迟指定时长通知，期间有同名但路径不同的创建或移除事件，则合并为一次从移除路径到创建路径的改名事件，
+    //用于在不同监听路径之间移动，或平台将改名报告为移除和创建时，仍然可以得到改名事件，
+    //为了保持事件的顺序，等待合并期间的后续事件也会延迟到等待合并的事件之后通知
+    pub fn set_rename_window(&mut self, time: u64) -> Result<(), String> {
+        if self.is_running() {
+            return Err(format!("set fs monitor rename window failed, already running"));
+        }
+
+        self.rename_window = time;
+        Ok(())
+    }
+
+    //设置事件队列的容量和队列已满时的处理策略，只允许在运行前设置，默认不限制容量
+    pub fn set_queue(&mut self, capacity: usize, policy: FSQueuePolicy) -> Result<(), String> {
+        if self.is_running() {
+            return Err(format!("set fs monitor queue failed, already running"));
+        }
+
+        if capacity == 0 {
+            return Err(format!("set fs monitor queue failed, invalid capacity"));
+        }
+
+        self.queue_capacity = capacity;
+        self.queue_policy = policy;
+        Ok(())
+    }
+
+    //判断监听器是否正在运行
+    pub fn is_running(&self) -> bool {
+        self.is_running.load(Ordering::Acquire)
+    }
+
+    //获取因事件队列已满而丢弃的事件数量
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
     }
 
     //检查是否监听了指定路径
     pub fn exists(&self, path: Atom) -> bool {
         let p = PathBuf::from(path.as_str());
-        self.watchers.contains_key(&p)
+        self.watches.lock().unwrap_or_else(|e| e.into_inner()).roots.contains_key(&p)
     }
 
-    //增加指定路径的监听
+    //获取所有监听的路径，与增加监听时的路径一致
+    pub fn watched(&self) -> Vec<PathBuf> {
+        let mut paths: Vec<PathBuf> = self.watches.lock().unwrap_or_else(|e| e.into_inner()).roots.keys().cloned().collect();
+        paths.sort();
+        paths
+    }
+
+    //增加指定路径的监听，只允许在运行时增加，增加后立即通知新路径的事件，
+    //设置了通知已存在的文件和目录时，先通知新路径下已存在的文件和目录，
+    //递归监听目录时，遍历期间已被移除或没有权限监听的子目录会被忽略，监听其它子目录失败则移除该监听路径并返回错误
     pub fn add_monitor(&mut self, options: FSMonitorOptions) -> Result<(), String> {
-        match self.watcher_sender.as_ref() {
-            None => Err(format!("add fs monitor failed, invalid sender")),
-            Some(sender) => add_monitor(&mut self.watchers, sender, &options),
+        if !self.is_running() {
+            return Err(format!("add fs monitor failed, not running"));
         }
+
+        add_monitor(&self.watches, &options, self.follow_symlinks, self.emit_existing)
     }
 
-    //移除指定路径的监听
+    //移除指定路径的监听，移除后不再通知该路径的事件，包括移除前已产生但还未通知的事件
     pub fn remove_monitor(&mut self, path: Atom) -> Result<(), String> {
-        remove_monitor(&mut self.watchers, &PathBuf::from(path.to_string()))
+        remove_monitor(&mut self.watches.lock().unwrap_or_else(|e| e.into_inner()), &PathBuf::from(path.to_string()))
     }
 
-    //运行指定监听器
+    //运行指定监听器，任一监听路径监听失败则移除所有监听路径并返回错误，
+    //递归监听目录时，遍历期间已被移除或没有权限监听的子目录会被忽略
     pub fn run(&mut self) -> Result<(), String> {
-        if self.is_running {
+        if self.is_running() {
             return Err(format!("fs monitor run failed, already running"));
         }
 
+        //监听者请求关闭后再次运行，需要先释放上次运行的线程
+        self.stop()?;
+
+        let (manager_sender, manager_receiver) = channel();
         let (sender, receiver) = channel();
-        let (p, c) = npnc_channel(1);
-        match add_monitor(&mut self.watchers, &sender, &self.options) {
-            Err(e) => return Err(e),
+        self.watches.lock().unwrap_or_else(|e| e.into_inner()).sender = Some(sender);
+        match add_monitor(&self.watches, &self.options, self.follow_symlinks, self.emit_existing) {
+            Err(e) => {
+                //移除已成功增加的监听
+                let _ = self.watches.lock().unwrap_or_else(|e| e.into_inner()).clear();
+                Err(e)
+            },
             Ok(_) => {
-                self.watcher_sender = Some(sender);
-                self.manager_sender = Some(p);
+                let queue = Arc::new(FSEventQueue::new(self.queue_capacity, self.queue_policy, self.dropped.clone()));
+                self.manager_sender = Some(manager_sender);
+                self.queue = Some(queue.clone());
+                self.is_running.store(true, Ordering::Release);
+
+                let follow_symlinks = self.follow_symlinks;
+                let filter = self.filter.clone();
+                let rename_window = self.rename_window;
+                let recv_queue = queue.clone();
+                let recv_watches = self.watches.clone();
+                self.handles.push(thread::spawn(move || {
+                    wait_recv(&recv_watches, &receiver, &recv_queue, &filter, rename_window, follow_symlinks);
+                }));
+
                 let listener = self.listener.clone();
-                thread::spawn(move || {
-                    wait_recv(&receiver, &c, &listener);
-                });
-                self.is_running = true;
+                let dispatch_watches = self.watches.clone();
+                let is_running = self.is_running.clone();
+                self.handles.push(thread::spawn(move || {
+                    wait_dispatch(&dispatch_watches, &queue, &manager_receiver, &listener, &is_running);
+                }));
                 Ok(())
             },
         }
@@ -135,46 +759,51 @@ impl FSMonitor {
 
     //暂停监听器
     pub fn pause(&self, time: usize) -> Result<(), String> {
-        if !self.is_running {
+        if !self.is_running() {
             return Err(format!("pause fs monitor failed, not running"));
         }
 
         match self.manager_sender.as_ref() {
             None => Err(format!("pause fs monitor failed, invalid sender")),
             Some(sender) => {
-                match sender.produce(FSMonitorEvent::Pause(time)) {
-                    Err(e) => {
-                        match e {
-                            ProduceError::Full(event) => Err(format!("pause fs monitor failed, event full, event: {:?}", event)),
-                            ProduceError::Disconnected(event) => Err(format!("pause fs monitor failed, monitor closed, event: {:?}", event)),
-                        }
-                    },
+                match sender.send(FSMonitorEvent::Pause(time)) {
+                    Err(SendError(event)) => Err(format!("pause fs monitor failed, monitor closed, event: {:?}", event)),
                     Ok(_) => Ok(()),
                 }
             },
         }
     }
 
-    //关闭监听器
-    pub fn stop(&self) -> Result<(), String> {
-        if !self.is_running {
-            return Err(format!("stop fs monitor failed, not running"));
+    //关闭监听器，并移除所有路径的监听，关闭后不会再通知监听者，可以重复关闭，也可以再次运行，
+    //监听者请求关闭后，仍然需要关闭以等待接收线程和通知线程退出
+    pub fn stop(&mut self) -> Result<(), String> {
+        self.is_running.store(false, Ordering::Release);
+
+        //通知监听线程关闭，监听线程已关闭则忽略
+        if let Some(sender) = self.manager_sender.take() {
+            let _ = sender.send(FSMonitorEvent::Stop);
         }
 
-        match self.manager_sender.as_ref() {
-            None => Err(format!("stop fs monitor failed, invalid sender")),
-            Some(sender) => {
-                match sender.produce(FSMonitorEvent::Stop) {
-                    Err(e) => {
-                        match e {
-                            ProduceError::Full(event) => Err(format!("stop fs monitor failed, event full, event: {:?}", event)),
-                            ProduceError::Disconnected(event) => Err(format!("stop fs monitor failed, monitor closed, event: {:?}", event)),
-                        }
-                    },
-                    Ok(_) => Ok(()),
+        //关闭事件队列，丢弃未通知的事件
+        if let Some(queue) = self.queue.take() {
+            queue.close();
+        }
+
+        //移除所有路径的监听，并释放监听器
+        let mut result = self.watches.lock().unwrap_or_else(|e| e.into_inner()).clear();
+
+        //等待监听线程退出，在监听者中关闭时不需要等待通知线程
+        for handle in self.handles.drain(..) {
+            if handle.thread().id() != thread::current().id() {
+                if let Err(e) = handle.join() {
+                    if result.is_ok() {
+                        result = Err(format!("stop fs monitor failed, join error, e: {:?}", e));
+                    }
                 }
-            },
+            }
         }
+
+        result
     }
 }
 
@@ -189,14 +818,14 @@ fn is_dir(path: &PathBuf) -> bool {
 }
 
 //增加监听器
-fn add_monitor(watchers: &mut HashMap<PathBuf, RecommendedWatcher>, sender: &Sender<DebouncedEvent>, options: &FSMonitorOptions) 
+fn add_monitor(watches: &Mutex<Watches>, options: &FSMonitorOptions, follow_symlinks: bool, emit_existing: bool) 
     -> Result<(), String> {
         let mut path: PathBuf;
         match options {
             FSMonitorOptions::File(file, time) => {
                 path = PathBuf::from(file.as_str());
                 if is_file(&path) {
-                    if let Err(e) = monitor_path(watchers, &sender, path, false, time.clone()) {
+                    if let Err(e) = monitor_file(watches, path, time.clone(), emit_existing) {
                         return Err(e);
                     }
                 } else {
@@ -207,7 +836,7 @@ fn add_monitor(watchers: &mut HashMap<PathBuf, RecommendedWatcher>, sender: &Sen
                 for (file, time) in files {
                     path = PathBuf::from(file.as_str());
                     if is_file(&path) {
-                        if let Err(e) = monitor_path(watchers, &sender, path, false, time.clone()) {
+                        if let Err(e) = monitor_file(watches, path, time.clone(), emit_existing) {
                             return Err(e);
                         }
                     } else {
@@ -218,7 +847,7 @@ fn add_monitor(watchers: &mut HashMap<PathBuf, RecommendedWatcher>, sender: &Sen
             FSMonitorOptions::Dir(dir, is_rec, time) => {
                 path = PathBuf::from(dir.as_str());
                 if is_dir(&path) {
-                    if let Err(e) = monitor_path(watchers, &sender, path, is_rec.clone(), time.clone()) {
+                    if let Err(e) = monitor_path(watches, path, is_rec.clone(), time.clone(), follow_symlinks, emit_existing) {
                         return Err(e);
                     }
                 } else {
@@ -229,7 +858,7 @@ fn add_monitor(watchers: &mut HashMap<PathBuf, RecommendedWatcher>, sender: &Sen
                 for (dir, is_rec, time) in dirs {
                     path = PathBuf::from(dir.as_str());
                     if is_dir(&path) {
-                        if let Err(e) = monitor_path(watchers, &sender, path, is_rec.clone(), time.clone()) {
+                        if let Err(e) = monitor_path(watches, path, is_rec.clone(), time.clone(), follow_symlinks, emit_existing) {
                             return Err(e);
                         }
                     } else {
@@ -241,99 +870,375 @@ fn add_monitor(watchers: &mut HashMap<PathBuf, RecommendedWatcher>, sender: &Sen
         Ok(())
 }
 
-//监听指定路径
-fn monitor_path(watchers: &mut HashMap<PathBuf, RecommendedWatcher>, sender: &Sender<DebouncedEvent>, path: PathBuf, is_rec: bool, time: u64) 
+//监听指定路径，路径为绝对路径，与文件事件的路径一致，递归监听时，在监听表外遍历并非递归的监听其下的每个目录
+fn monitor_path(watches: &Mutex<Watches>, path: PathBuf, is_rec: bool, time: u64, follow_symlinks: bool, emit_existing: bool) 
     -> Result<(), String> {
-        if watchers.contains_key(&path) {
-            //指定路径已监听
-            return Err(format!("add fs monitor failed, path exists"));
+        let dir = absolute_path(path.clone());
+        let root = WatchRoot { path: dir.clone(), dir: dir.clone(), file: None, is_rec, time, dirs: HashSet::new() };
+        watches.lock().unwrap_or_else(|e| e.into_inner()).add_root(&path, root, emit_existing)?;
+
+        if is_rec {
+            let mut visited = HashSet::new();
+            if let Ok(real) = dir.canonicalize() {
+                visited.insert(real);
+            }
+            for entry in read_entries(&dir) {
+                if let Err(e) = watch_dirs(watches, &path, &entry, follow_symlinks, &mut visited, None) {
+                    //移除已成功增加的监听
+                    let _ = watches.lock().unwrap_or_else(|e| e.into_inner()).remove_root(&path);
+                    return Err(format!("add fs monitor failed, {}", e));
+                }
+            }
         }
+        Ok(())
+}
 
-        match watcher(sender.clone(), Duration::from_millis(time)) {
-            Err(e) => Err(format!("add fs monitor failed, path: {:?}, e: {:?}", path, e)),
-            Ok(mut watcher) => {
-                let mode = if is_rec {
-                    RecursiveMode::Recursive
-                } else {
-                    RecursiveMode::NonRecursive
-                };
+//监听指定文件，实际非递归的监听文件所在的目录，并只接收指定文件的事件，保证文件被替换或重建后仍然可以继续监听
+fn monitor_file(watches: &Mutex<Watches>, path: PathBuf, time: u64, emit_existing: bool) 
+    -> Result<(), String> {
+        let file = match path.canonicalize() {
+            Err(e) => return Err(format!("add fs monitor failed, path: {:?}, e: {:?}", path, e)),
+            Ok(file) => file,
+        };
+        let dir = match file.parent() {
+            None => return Err(format!("add fs monitor failed, invalid parent, path: {:?}", path)),
+            Some(dir) => dir.to_path_buf(),
+        };
 
-                match watcher.watch(&path, mode) {
-                    Err(e) => Err(format!("add fs monitor failed, path: {:?}, e: {:?}", path, e)),
-                    Ok(_) => {
-                        watchers.insert(path, watcher);
-                        Ok(())
-                    }
-                }
-            },
+        let root = WatchRoot { path: file, dir, file: Some(true), is_rec: false, time, dirs: HashSet::new() };
+        watches.lock().unwrap_or_else(|e| e.into_inner()).add_root(&path, root, emit_existing)
+}
+
+//非递归的监听指定监听路径下的指定目录及其所有子目录，每个目录监听后才读取其中的文件和目录，只在监听时持有监听表，
+//该监听路径已监听的目录则忽略，指定了事件列表时，将目录下已存在的文件和目录作为创建事件，因为新建的目录在监听前创建的文件和目录没有事件，
+//遍历期间已被移除或没有权限监听的目录会被忽略，其它监听错误，例如超出系统的监听数量限制，则停止遍历并返回错误
+fn watch_dirs(watches: &Mutex<Watches>, key: &Path, dir: &Path, follow_symlinks: bool, visited: &mut HashSet<PathBuf>, mut existing: Option<&mut Vec<FSEvent>>) -> Result<(), String> {
+    if !is_walk_dir(dir, follow_symlinks, visited) {
+        return Ok(());
+    }
+
+    match watches.lock().unwrap_or_else(|e| e.into_inner()).watch_dir(key, dir) {
+        Err(ref e) if is_ignored_error(e) => return Ok(()),
+        Err(e) => return Err(format!("watch dir failed, path: {:?}, e: {:?}", dir, e)),
+        Ok(false) => return Ok(()), //未运行、监听路径已移除或已监听该目录
+        Ok(true) => (),
+    }
+    for entry in read_entries(dir) {
+        if let Some(events) = existing.as_mut() {
+            events.push(FSEvent::new(key.to_path_buf(), entry.clone(), FSEventKind::Created, SystemTime::now()));
         }
+
+        watch_dirs(watches, key, &entry, follow_symlinks, visited, existing.as_deref_mut())?;
+    }
+    Ok(())
+}
+
+//判断监听目录的错误是否可以忽略，只忽略目录不存在和没有权限
+fn is_ignored_error(e: &notify::Error) -> bool {
+    match e {
+        notify::Error::PathNotFound => true,
+        notify::Error::Io(e) => e.kind() == io::ErrorKind::NotFound || e.kind() == io::ErrorKind::PermissionDenied,
+        _ => false,
+    }
+}
+
+//判断路径是否是需要遍历的目录，跟随符号链接时不重复进入同一个实际目录，不跟随时不进入符号链接的目录
+fn is_walk_dir(path: &Path, follow_symlinks: bool, visited: &mut HashSet<PathBuf>) -> bool {
+    if !follow_symlinks {
+        return is_real_dir(path);
+    }
+
+    match fs::metadata(path) {
+        Ok(ref meta) if meta.is_dir() => {
+            path.canonicalize().map(|real| visited.insert(real)).unwrap_or(false)
+        },
+        _ => false,
+    }
+}
+
+//判断路径是否是实际目录，不跟随符号链接
+fn is_real_dir(path: &Path) -> bool {
+    match fs::symlink_metadata(path) {
+        Err(_) => false,
+        Ok(meta) => meta.is_dir(),
+    }
+}
+
+//获取指定目录下的所有文件和目录，按路径排序，无法读取的目录则为空
+fn read_entries(dir: &Path) -> Vec<PathBuf> {
+    let mut entries: Vec<PathBuf> = match fs::read_dir(dir) {
+        Err(_) => return Vec::new(),
+        Ok(dir) => dir.filter_map(|entry| entry.ok().map(|entry| entry.path())).collect(),
+    };
+    entries.sort();
+    entries
+}
+
+//获取缓冲后收到的事件的发生时间，缓冲会在该路径最后一次事件后的缓冲时间结束时发送事件，因此为当前时间减去缓冲时间
+fn event_time(time: u64) -> SystemTime {
+    let now = SystemTime::now();
+    now.checked_sub(Duration::from_millis(time)).unwrap_or(now)
 }
 
 //移除监听器
-fn remove_monitor(watchers: &mut HashMap<PathBuf, RecommendedWatcher>, path: &PathBuf) -> Result<(), String> {
-    match watchers.remove_entry(path) {
-        None => Ok(()), //指定路径的监听不存在，则忽略
-        Some((p, mut wathcer)) => {
-            //指定路径的监听存在，则关闭监听
-            match wathcer.unwatch(p) {
-                Err(e) => Err(format!("remove fs monitor failed, path: {:?}, e: {:?}", path, e)),
-                Ok(_) => Ok(()),
+fn remove_monitor(watches: &mut Watches, path: &PathBuf) -> Result<(), String> {
+    //指定路径的监听不存在，则忽略
+    match watches.remove_root(path) {
+        Err(e) => Err(format!("remove fs monitor failed, path: {:?}, e: {:?}", path, e)),
+        Ok(_) => Ok(()),
+    }
+}
+
+//获取绝对路径，相对路径基于当前目录，与文件事件的路径一致
+fn absolute_path(path: PathBuf) -> PathBuf {
+    if path.is_absolute() {
+        return path;
+    }
+
+    match env::current_dir() {
+        Err(_) => path,
+        Ok(dir) => dir.join(path),
+    }
+}
+
+//将指定路径下已存在的文件和目录作为创建事件加入事件队列，事件队列已关闭则返回false
+fn scan_existing(root: &Path, path: &Path, is_rec: Option<bool>, follow_symlinks: bool, queue: &FSEventQueue, filter: &Option<FSFilter>) -> bool {
+    match is_rec {
+        None => {
+            //监听文件
+            push_event(queue, filter, FSEvent::new(root.to_path_buf(), path.to_path_buf(), FSEventKind::Created, SystemTime::now()))
+        },
+        Some(is_rec) => {
+            let mut visited = HashSet::new();
+            if let Ok(real) = path.canonicalize() {
+                visited.insert(real);
             }
+            scan_dir(root, path, is_rec, follow_symlinks, &mut visited, queue, filter)
+        },
+    }
+}
+
+//将指定目录下已存在的文件和目录作为创建事件加入事件队列，跟随符号链接时不重复进入同一个目录，不跟随时不进入符号链接的目录，
+//事件队列已关闭则返回false
+fn scan_dir(root: &Path, path: &Path, is_rec: bool, follow_symlinks: bool, visited: &mut HashSet<PathBuf>, queue: &FSEventQueue, filter: &Option<FSFilter>) -> bool {
+    for entry in read_entries(path) {
+        if !push_event(queue, filter, FSEvent::new(root.to_path_buf(), entry.clone(), FSEventKind::Created, SystemTime::now())) {
+            return false;
+        }
+
+        if !is_rec {
+            continue;
+        }
+
+        if is_walk_dir(&entry, follow_symlinks, visited) && !scan_dir(root, &entry, is_rec, follow_symlinks, visited, queue, filter) {
+            return false;
         }
     }
+    true
 }
 
-//等待接收事件，并通知监听者
-fn wait_recv(receiver: &Receiver<DebouncedEvent>, consumer: &Consumer<FSMonitorEvent>, listener: &FSListener) {
+//阻塞的等待接收所有监听器的事件，按事件的路径转换为所属监听路径的文件事件，合并移除和创建为改名后，加入事件队列，
+//新的监听路径需要通知已存在的文件和目录时，先通知已存在的文件和目录，遍历目录时不持有监听表
+fn wait_recv(watches: &Mutex<Watches>, receiver: &Receiver<DebouncedEvent>, queue: &FSEventQueue, filter: &Option<FSFilter>, rename_window: u64, follow_symlinks: bool) {
+    let mut pending: VecDeque<(Instant, FSEvent)> = VecDeque::new(); //等待通知的事件和通知时间
     loop {
-        //处理管理事件
-        match consumer.consume() {
-            Err(e) => {
-                match e {
-                    ConsumeError::Disconnected => {
-                        //所有者已关闭，则立即退出监听线程
-                        println!("!!!> Close Fs Monitor, owner closed");
-                        break;
-                    },
-                    ConsumeError::Empty => (), //没有管理事件，则忽略
+        //将已到通知时间的事件按顺序加入事件队列
+        let now = Instant::now();
+        while let Some((time, _)) = pending.front() {
+            if *time > now {
+                break;
+            }
+
+            if let Some((_, event)) = pending.pop_front() {
+                if !push_event(queue, filter, event) {
+                    //事件队列已关闭，则立即退出接收线程
+                    return;
                 }
-            },
+            }
+        }
+
+        //等待文件事件，直到下一个等待通知的事件的通知时间，超时后检查事件队列是否已关闭
+        let timeout = match pending.front() {
+            None => Duration::from_millis(WAIT_EVENT_TIMEOUT),
+            Some((time, _)) => time.saturating_duration_since(now),
+        };
+        let mut received = Vec::new();
+        match receiver.recv_timeout(timeout) {
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => break, //所有监听器已释放，则立即退出接收线程
             Ok(event) => {
-                match event {
-                    FSMonitorEvent::Stop => {
-                        //所有者请求关闭监听线程
-                        println!("!!!> Close Fs Monitor, owner request close");
-                        break;
-                    },
-                    FSMonitorEvent::Pause(time) => {
-                        //所有者请求暂停监听线程指定时长
-                        thread::sleep(Duration::from_millis(time as u64));
-                    }
+                received.push(event);
+                while let Ok(event) = receiver.try_recv() {
+                    received.push(event);
                 }
+            },
+        }
+        if queue.is_closed() {
+            //事件队列已关闭，则立即退出接收线程
+            break;
+        }
+
+        //转换所有文件事件，并取出需要通知已存在的文件和目录的监听路径
+        let mut events = Vec::new();
+        let mut walks = Vec::new();
+        let existing = {
+            let mut watches = watches.lock().unwrap_or_else(|e| e.into_inner());
+            for event in received {
+                watches.recv(event, &mut events, &mut walks);
+            }
+            mem::take(&mut watches.existing)
+        };
+
+        //先通知新的监听路径下已存在的文件和目录，遍历时不持有监听表，避免事件队列已满时阻塞通知线程
+        for (root, path, is_rec) in existing {
+            if !scan_existing(&root, &path, is_rec, follow_symlinks, queue, filter) {
+                //事件队列已关闭，则立即退出接收线程
+                return;
+            }
+        }
+
+        //监听新建或移入的目录，新建的目录中已存在的文件和目录在该目录的创建事件后通知
+        for (root, dir, is_created) in walks {
+            let existing = if is_created {
+                Some(&mut events)
+            } else {
+                None
+            };
+            if let Err(e) = watch_dirs(watches, &root, &dir, follow_symlinks, &mut HashSet::new(), existing) {
+                //无法监听的目录不会再通知事件，但不影响其它目录的监听
+                println!("!!!> Fs Monitor Watch Dir Error, root: {:?}, e: {}", root, e);
             }
         }
 
-        //等待处理文件事件
-        match receiver.recv() {
-            Ok(DebouncedEvent::Write(path)) => {
-                (listener.0)(FSChangeEvent::Write(path));
+        let now = Instant::now();
+        for event in events {
+            pend_event(&mut pending, event, now, rename_window);
+        }
+    }
+}
+
+//将事件加入等待通知的事件，移除或创建事件会与等待合并的同名创建或移除事件合并为改名，否则延迟通知，等待合并为改名
+fn pend_event(pending: &mut VecDeque<(Instant, FSEvent)>, event: FSEvent, now: Instant, rename_window: u64) {
+    let other_kind = match event.kind {
+        FSEventKind::Removed if rename_window > 0 => FSEventKind::Created,
+        FSEventKind::Created if rename_window > 0 => FSEventKind::Removed,
+        _ => {
+            pending.push_back((now, event));
+            return;
+        },
+    };
+
+    let index = pending.iter().position(|(_, other)| {
+        other.kind == other_kind
+            && other.path != event.path
+            && other.path.file_name() == event.path.file_name()
+    });
+    match index.and_then(|index| pending.remove(index)) {
+        None => pending.push_back((now + Duration::from_millis(rename_window), event)),
+        Some((_, other)) => {
+            let (removed, created) = if event.kind == FSEventKind::Removed {
+                (event, other)
+            } else {
+                (other, event)
+            };
+            let time = removed.time.max(created.time);
+            let renamed = FSEvent::new(created.root, created.path.clone(), FSEventKind::Renamed { from: removed.path, to: created.path }, time);
+            pending.push_back((now, renamed));
+        },
+    }
+}
+
+//将未被过滤的事件加入事件队列，事件队列已关闭则返回false
+fn push_event(queue: &FSEventQueue, filter: &Option<FSFilter>, event: FSEvent) -> bool {
+    if let Some(filter) = filter {
+        if !filter.is_match(&event) {
+            return true;
+        }
+    }
+
+    queue.push(event)
+}
+
+//等待事件队列中的事件，并通知监听者，忽略监听路径已移除的事件，监听者请求关闭时移除所有路径的监听
+fn wait_dispatch(watches: &Mutex<Watches>, queue: &FSEventQueue, manager: &Receiver<FSMonitorEvent>, listener: &MonitorListener, is_running: &AtomicBool) {
+    loop {
+        //处理管理事件
+        if !handle_manager(manager) {
+            break;
+        }
+
+        match queue.pop(Duration::from_millis(WAIT_EVENT_TIMEOUT)) {
+            None => {
+                if queue.is_closed() {
+                    //事件队列已关闭，则立即退出通知线程
+                    break;
+                }
+            },
+            Some(event) => {
+                //通知监听者前处理管理事件，保证关闭后不会再通知监听者
+                if !handle_manager(manager) {
+                    break;
+                }
+
+                if !watches.lock().unwrap_or_else(|e| e.into_inner()).roots.contains_key(&event.root) {
+                    //监听路径已移除，则忽略该路径的事件
+                    continue;
+                }
+
+                if let ControlFlow::Break(_) = listener.notify(event) {
+                    //监听者请求关闭，则关闭事件队列，移除所有路径的监听，并立即退出通知线程
+                    is_running.store(false, Ordering::Release);
+                    queue.close();
+                    let _ = watches.lock().unwrap_or_else(|e| e.into_inner()).clear();
+                    break;
+                }
             },
-            Ok(DebouncedEvent::Remove(path)) => {
-                (listener.0)(FSChangeEvent::Remove(path));
+        }
+    }
+}
+
+//处理管理事件，返回false表示需要退出监听线程
+fn handle_manager(manager: &Receiver<FSMonitorEvent>) -> bool {
+    let mut pause_until: Option<Instant> = None;
+    loop {
+        let event = match pause_until {
+            None => {
+                match manager.try_recv() {
+                    Err(TryRecvError::Empty) => return true, //没有管理事件，则继续监听
+                    Err(TryRecvError::Disconnected) => {
+                        //所有者已关闭，则立即退出监听线程
+                        println!("!!!> Close Fs Monitor, owner closed");
+                        return false;
+                    },
+                    Ok(event) => event,
+                }
             },
-            Ok(DebouncedEvent::Create(path)) => {
-                (listener.0)(FSChangeEvent::Create(path));
+            Some(until) => {
+                //暂停期间仍然响应管理事件
+                match manager.recv_timeout(until.saturating_duration_since(Instant::now())) {
+                    Err(RecvTimeoutError::Timeout) => {
+                        pause_until = None;
+                        continue;
+                    },
+                    Err(RecvTimeoutError::Disconnected) => {
+                        println!("!!!> Close Fs Monitor, owner closed");
+                        return false;
+                    },
+                    Ok(event) => event,
+                }
             },
-            Ok(DebouncedEvent::Rename(src, dst)) => {
-                (listener.0)(FSChangeEvent::Rename(src, dst));
+        };
+
+        match event {
+            FSMonitorEvent::Stop => {
+                //所有者请求关闭监听线程
+                println!("!!!> Close Fs Monitor, owner request close");
+                return false;
             },
-            Err(e) => {
-                //对端已关闭，则立即退出监听线程
-                println!("!!!> Close Fs Monitor, peer closed, e: {:?}", e);
-                break;
+            FSMonitorEvent::Pause(time) => {
+                //所有者请求暂停监听线程指定时长
+                pause_until = Some(Instant::now() + Duration::from_millis(time as u64));
             },
-            _ => (),
         }
     }
 }
-
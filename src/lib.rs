@@ -6,7 +6,6 @@
 #![feature(slice_internals)]
 // #![feature(integer_atomics)]
 
-extern crate npnc;
 extern crate notify;
 extern crate pi_atom;
 
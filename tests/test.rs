@@ -1,15 +1,35 @@
-extern crate npnc;
-
-
 extern crate pi_file;
 extern crate pi_atom;
 
 use std::thread;
-use std::sync::Arc;
+use std::fs;
+use std::env;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::ops::ControlFlow;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
 
-use pi_file::fs_monitor::{FSMonitorOptions, FSListener, FSMonitor};
+use pi_file::fs_monitor::{FSMonitorOptions, FSListener, FSListenerControl, FSMonitor, FSEvent, FSEventKind, FSFilter, FSQueuePolicy};
 use pi_atom::Atom;
 
+//构建一个按顺序记录所有事件的监听者
+fn recording_listener() -> (Arc<Mutex<Vec<FSEvent>>>, FSListener) {
+    let events: Arc<Mutex<Vec<FSEvent>>> = Arc::new(Mutex::new(Vec::new()));
+    let events_copy = events.clone();
+    let listener = FSListener(Arc::new(move |event| {
+        events_copy.lock().unwrap().push(event);
+    }));
+    (events, listener)
+}
+
+//构建一个空的临时测试目录
+fn fresh_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(name);
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
 
 #[test]
 fn test_fs_monitor() {
@@ -18,8 +38,625 @@ fn test_fs_monitor() {
     }));
     let mut monitor = FSMonitor::new(FSMonitorOptions::Dir(Atom::from("test"), true, 3000), listener);
     if let Ok(_) = monitor.run() {
-        loop {
-            thread::sleep(std::time::Duration::from_millis(1000));
+        thread::sleep(Duration::from_millis(1000));
+        monitor.stop().unwrap();
+    }
+}
+
+#[test]
+fn test_fs_monitor_stop() {
+    let dir = fresh_dir("pi_file_test_stop");
+
+    let count = Arc::new(AtomicUsize::new(0));
+    let count_copy = count.clone();
+    let listener = FSListener(Arc::new(move |_event| {
+        count_copy.fetch_add(1, Ordering::SeqCst);
+    }));
+    let mut monitor = FSMonitor::new(FSMonitorOptions::Dir(Atom::from(dir.to_str().unwrap()), true, 100), listener);
+    monitor.run().unwrap();
+    assert!(monitor.exists(Atom::from(dir.to_str().unwrap())));
+
+    fs::write(dir.join("a.txt"), b"a").unwrap();
+    thread::sleep(Duration::from_millis(500));
+    assert!(count.load(Ordering::SeqCst) > 0);
+
+    //关闭后不再通知监听者，且可以重复关闭
+    monitor.stop().unwrap();
+    monitor.stop().unwrap();
+    assert!(!monitor.exists(Atom::from(dir.to_str().unwrap())));
+    let stopped = count.load(Ordering::SeqCst);
+    fs::write(dir.join("b.txt"), b"b").unwrap();
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(count.load(Ordering::SeqCst), stopped);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_fs_monitor_file() {
+    let dir = fresh_dir("pi_file_test_file");
+    let file = dir.join("config.json");
+    fs::write(&file, b"0").unwrap();
+
+    let (events, listener) = recording_listener();
+    let mut monitor = FSMonitor::new(FSMonitorOptions::File(Atom::from(file.to_str().unwrap()), 100), listener);
+    monitor.run().unwrap();
+    thread::sleep(Duration::from_millis(200));
+
+    //同目录下的其它文件不会通知
+    fs::write(dir.join("other.json"), b"0").unwrap();
+    thread::sleep(Duration::from_millis(400));
+    assert!(events.lock().unwrap().is_empty());
+
+    //文件被替换视为修改，且替换后仍然继续监听
+    let tmp = dir.join("config.json.tmp");
+    fs::write(&tmp, b"1").unwrap();
+    fs::rename(&tmp, &file).unwrap();
+    thread::sleep(Duration::from_millis(400));
+    fs::remove_file(&file).unwrap();
+    thread::sleep(Duration::from_millis(400));
+
+    let kinds: Vec<FSEventKind> = events.lock().unwrap().iter().map(|e| e.kind.clone()).collect();
+    assert_eq!(kinds, vec![FSEventKind::Modified, FSEventKind::Removed]);
+
+    monitor.stop().unwrap();
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_fs_monitor_file_recreate() {
+    let dir = fresh_dir("pi_file_test_file_recreate");
+    let file = dir.join("config.json");
+    fs::write(&file, b"0").unwrap();
+
+    let (events, listener) = recording_listener();
+    let mut monitor = FSMonitor::new(FSMonitorOptions::File(Atom::from(file.to_str().unwrap()), 100), listener);
+    monitor.run().unwrap();
+    thread::sleep(Duration::from_millis(200));
+
+    //文件被移除后重建视为创建，且重建后仍然继续监听
+    fs::remove_file(&file).unwrap();
+    thread::sleep(Duration::from_millis(400));
+    fs::write(&file, b"1").unwrap();
+    thread::sleep(Duration::from_millis(400));
+    fs::write(&file, b"2").unwrap();
+    thread::sleep(Duration::from_millis(400));
+
+    let kinds: Vec<FSEventKind> = events.lock().unwrap().iter().map(|e| e.kind.clone()).collect();
+    assert_eq!(kinds, vec![FSEventKind::Removed, FSEventKind::Created, FSEventKind::Modified]);
+
+    monitor.stop().unwrap();
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_fs_monitor_non_recursive() {
+    let dir = fresh_dir("pi_file_test_non_recursive");
+    fs::create_dir_all(dir.join("sub")).unwrap();
+
+    let (events, listener) = recording_listener();
+    let mut monitor = FSMonitor::new(FSMonitorOptions::Dir(Atom::from(dir.to_str().unwrap()), false, 100), listener);
+    monitor.run().unwrap();
+    thread::sleep(Duration::from_millis(200));
+
+    //子目录中的事件不会通知
+    fs::write(dir.join("sub").join("nested.txt"), b"0").unwrap();
+    fs::create_dir_all(dir.join("sub").join("deep")).unwrap();
+    fs::write(dir.join("top.txt"), b"0").unwrap();
+    thread::sleep(Duration::from_millis(400));
+
+    let paths: Vec<_> = events.lock().unwrap().iter().map(|e| e.path.clone()).collect();
+    assert_eq!(paths, vec![dir.join("top.txt")]);
+
+    monitor.stop().unwrap();
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_fs_monitor_filter() {
+    let dir = fresh_dir("pi_file_test_filter");
+    fs::write(dir.join("d.txt"), b"0").unwrap();
+    fs::write(dir.join("e.txt"), b"0").unwrap();
+
+    let (events, listener) = recording_listener();
+    let mut monitor = FSMonitor::new(FSMonitorOptions::Dir(Atom::from(dir.to_str().unwrap()), true, 100), listener);
+    monitor.set_filter(FSFilter::with_extensions(&["png", "JSON"])).unwrap();
+    monitor.run().unwrap();
+    thread::sleep(Duration::from_millis(200));
+
+    //只通知指定扩展名的路径，且忽略扩展名的大小写
+    fs::write(dir.join("a.png"), b"0").unwrap();
+    thread::sleep(Duration::from_millis(300));
+    fs::write(dir.join("b.json"), b"0").unwrap();
+    thread::sleep(Duration::from_millis(300));
+    fs::write(dir.join("c.txt"), b"0").unwrap();
+    thread::sleep(Duration::from_millis(300));
+    fs::write(dir.join("C.PNG"), b"0").unwrap();
+    thread::sleep(Duration::from_millis(300));
+
+    //改名的源路径或目标路径满足过滤条件即通知
+    fs::rename(dir.join("d.txt"), dir.join("d.png")).unwrap();
+    thread::sleep(Duration::from_millis(300));
+    fs::rename(dir.join("b.json"), dir.join("b.txt")).unwrap();
+    thread::sleep(Duration::from_millis(300));
+    fs::rename(dir.join("e.txt"), dir.join("f.txt")).unwrap();
+    thread::sleep(Duration::from_millis(300));
+
+    let kinds: Vec<_> = events.lock().unwrap().iter().map(|e| (e.path.clone(), e.kind.clone())).collect();
+    assert_eq!(kinds, vec![(dir.join("a.png"), FSEventKind::Created),
+                           (dir.join("b.json"), FSEventKind::Created),
+                           (dir.join("C.PNG"), FSEventKind::Created),
+                           (dir.join("d.png"), FSEventKind::Renamed { from: dir.join("d.txt"), to: dir.join("d.png") }),
+                           (dir.join("b.txt"), FSEventKind::Renamed { from: dir.join("b.json"), to: dir.join("b.txt") })]);
+
+    monitor.stop().unwrap();
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_fs_monitor_queue() {
+    let dir = fresh_dir("pi_file_test_queue");
+
+    let count = Arc::new(AtomicUsize::new(0));
+    let count_copy = count.clone();
+    let listener = FSListener(Arc::new(move |_event| {
+        //模拟处理缓慢的监听者
+        thread::sleep(Duration::from_millis(100));
+        count_copy.fetch_add(1, Ordering::SeqCst);
+    }));
+    let mut monitor = FSMonitor::new(FSMonitorOptions::Dir(Atom::from(dir.to_str().unwrap()), true, 100), listener);
+    assert!(monitor.set_queue(0, FSQueuePolicy::DropNewest).is_err());
+    monitor.set_queue(2, FSQueuePolicy::DropNewest).unwrap();
+    monitor.run().unwrap();
+    thread::sleep(Duration::from_millis(200));
+
+    for i in 0..10 {
+        fs::write(dir.join(format!("{}.txt", i)), b"0").unwrap();
+    }
+    thread::sleep(Duration::from_millis(1500));
+
+    //队列已满时丢弃新事件，并记录丢弃的事件数量
+    assert!(monitor.dropped() > 0);
+    assert_eq!(count.load(Ordering::SeqCst) + monitor.dropped(), 10);
+
+    monitor.stop().unwrap();
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_fs_monitor_queue_drop_oldest() {
+    let dir = fresh_dir("pi_file_test_queue_drop_oldest");
+
+    let events: Arc<Mutex<Vec<FSEvent>>> = Arc::new(Mutex::new(Vec::new()));
+    let events_copy = events.clone();
+    let listener = FSListener(Arc::new(move |event| {
+        //模拟处理缓慢的监听者
+        thread::sleep(Duration::from_millis(100));
+        events_copy.lock().unwrap().push(event);
+    }));
+    let mut monitor = FSMonitor::new(FSMonitorOptions::Dir(Atom::from(dir.to_str().unwrap()), true, 100), listener);
+    monitor.set_queue(2, FSQueuePolicy::DropOldest).unwrap();
+    monitor.run().unwrap();
+    thread::sleep(Duration::from_millis(200));
+
+    for i in 0..10 {
+        fs::write(dir.join(format!("{}.txt", i)), b"0").unwrap();
+    }
+    thread::sleep(Duration::from_millis(1500));
+
+    //队列已满时丢弃最早的事件，因此最新的事件一定会通知
+    let paths: Vec<_> = events.lock().unwrap().iter().map(|e| e.path.clone()).collect();
+    assert!(monitor.dropped() > 0);
+    assert_eq!(paths.len() + monitor.dropped(), 10);
+    assert_eq!(paths[paths.len() - 2..].to_vec(), vec![dir.join("8.txt"), dir.join("9.txt")]);
+
+    monitor.stop().unwrap();
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_fs_monitor_queue_block() {
+    let dir = fresh_dir("pi_file_test_queue_block");
+
+    let events: Arc<Mutex<Vec<FSEvent>>> = Arc::new(Mutex::new(Vec::new()));
+    let events_copy = events.clone();
+    let listener = FSListener(Arc::new(move |event| {
+        //模拟处理缓慢的监听者
+        thread::sleep(Duration::from_millis(50));
+        events_copy.lock().unwrap().push(event);
+    }));
+    let mut monitor = FSMonitor::new(FSMonitorOptions::Dir(Atom::from(dir.to_str().unwrap()), true, 100), listener);
+    monitor.set_queue(2, FSQueuePolicy::Block).unwrap();
+    monitor.run().unwrap();
+    thread::sleep(Duration::from_millis(200));
+
+    for i in 0..10 {
+        fs::write(dir.join(format!("{}.txt", i)), b"0").unwrap();
+    }
+    thread::sleep(Duration::from_millis(1500));
+
+    //队列已满时阻塞接收，不丢弃事件，所有事件最终都会按顺序通知
+    let paths: Vec<_> = events.lock().unwrap().iter().map(|e| e.path.clone()).collect();
+    assert_eq!(monitor.dropped(), 0);
+    assert_eq!(paths, (0..10).map(|i| dir.join(format!("{}.txt", i))).collect::<Vec<_>>());
+
+    monitor.stop().unwrap();
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_fs_monitor_emit_existing() {
+    let dir = fresh_dir("pi_file_test_emit_existing");
+    fs::create_dir_all(dir.join("sub")).unwrap();
+    fs::write(dir.join("a.txt"), b"0").unwrap();
+    fs::write(dir.join("sub").join("b.txt"), b"0").unwrap();
+
+    let (events, listener) = recording_listener();
+    let mut monitor = FSMonitor::new(FSMonitorOptions::Dir(Atom::from(dir.to_str().unwrap()), true, 100), listener);
+    monitor.set_emit_existing(true).unwrap();
+    monitor.run().unwrap();
+    assert!(monitor.set_emit_existing(false).is_err());
+    thread::sleep(Duration::from_millis(200));
+
+    //已存在的文件和目录先作为创建事件通知，再通知新的事件
+    fs::write(dir.join("c.txt"), b"0").unwrap();
+    thread::sleep(Duration::from_millis(400));
+
+    let kinds: Vec<_> = events.lock().unwrap().drain(..).map(|e| (e.path, e.kind)).collect();
+    assert_eq!(kinds, vec![(dir.join("a.txt"), FSEventKind::Created),
+                           (dir.join("sub"), FSEventKind::Created),
+                           (dir.join("sub").join("b.txt"), FSEventKind::Created),
+                           (dir.join("c.txt"), FSEventKind::Created)]);
+
+    //运行时增加的监听路径，也先通知已存在的文件和目录
+    let other = fresh_dir("pi_file_test_emit_existing_other");
+    fs::write(other.join("d.txt"), b"0").unwrap();
+    monitor.add_monitor(FSMonitorOptions::Dir(Atom::from(other.to_str().unwrap()), true, 100)).unwrap();
+    thread::sleep(Duration::from_millis(200));
+    fs::write(other.join("e.txt"), b"0").unwrap();
+    thread::sleep(Duration::from_millis(400));
+
+    let kinds: Vec<_> = events.lock().unwrap().drain(..).map(|e| (e.root, e.path, e.kind)).collect();
+    assert_eq!(kinds, vec![(other.clone(), other.join("d.txt"), FSEventKind::Created),
+                           (other.clone(), other.join("e.txt"), FSEventKind::Created)]);
+
+    monitor.stop().unwrap();
+    let _ = fs::remove_dir_all(&dir);
+    let _ = fs::remove_dir_all(&other);
+}
+
+#[test]
+fn test_fs_monitor_dirs() {
+    let dir = fresh_dir("pi_file_test_dirs");
+    let dir_a = dir.join("a");
+    let dir_b = dir.join("b");
+    let dir_c = dir.join("c");
+    fs::create_dir_all(&dir_a).unwrap();
+    fs::create_dir_all(&dir_b).unwrap();
+    fs::create_dir_all(&dir_c).unwrap();
+
+    let (events, listener) = recording_listener();
+    let mut monitor = FSMonitor::new(FSMonitorOptions::Dirs(vec![(Atom::from(dir_a.to_str().unwrap()), true, 100),
+                                                                 (Atom::from(dir_b.to_str().unwrap()), true, 100)]), listener);
+    monitor.run().unwrap();
+    monitor.add_monitor(FSMonitorOptions::Dir(Atom::from(dir_c.to_str().unwrap()), true, 100)).unwrap();
+    thread::sleep(Duration::from_millis(200));
+
+    //所有监听路径的事件都通知同一个监听者，并标记产生事件的监听路径
+    fs::write(dir_a.join("1.txt"), b"0").unwrap();
+    thread::sleep(Duration::from_millis(300));
+    fs::write(dir_b.join("2.txt"), b"0").unwrap();
+    thread::sleep(Duration::from_millis(300));
+    fs::write(dir_c.join("3.txt"), b"0").unwrap();
+    thread::sleep(Duration::from_millis(300));
+
+    let roots: Vec<_> = events.lock().unwrap().iter().map(|e| (e.root.clone(), e.path.clone())).collect();
+    assert_eq!(roots, vec![(dir_a.clone(), dir_a.join("1.txt")),
+                           (dir_b.clone(), dir_b.join("2.txt")),
+                           (dir_c.clone(), dir_c.join("3.txt"))]);
+
+    monitor.stop().unwrap();
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_fs_monitor_overlap() {
+    let dir = fresh_dir("pi_file_test_overlap");
+    let sub = dir.join("sub");
+    fs::create_dir_all(&sub).unwrap();
+    fs::write(dir.join("f.txt"), b"0").unwrap();
+
+    let (events, listener) = recording_listener();
+    let mut monitor = FSMonitor::new(FSMonitorOptions::Dir(Atom::from(dir.to_str().unwrap()), true, 100), listener);
+    monitor.run().unwrap();
+    monitor.add_monitor(FSMonitorOptions::Dir(Atom::from(sub.to_str().unwrap()), true, 200)).unwrap();
+    monitor.add_monitor(FSMonitorOptions::File(Atom::from(dir.join("f.txt").to_str().unwrap()), 100)).unwrap();
+    thread::sleep(Duration::from_millis(200));
+
+    //监听路径重叠时，每个文件事件只通知一次，且为最具体的监听路径
+    fs::write(sub.join("1.txt"), b"0").unwrap();
+    thread::sleep(Duration::from_millis(300));
+    fs::write(dir.join("2.txt"), b"0").unwrap();
+    thread::sleep(Duration::from_millis(300));
+    fs::write(dir.join("f.txt"), b"1").unwrap();
+    thread::sleep(Duration::from_millis(300));
+
+    let roots: Vec<_> = events.lock().unwrap().iter().map(|e| (e.root.clone(), e.path.clone())).collect();
+    assert_eq!(roots, vec![(sub.clone(), sub.join("1.txt")),
+                           (dir.clone(), dir.join("2.txt")),
+                           (dir.join("f.txt"), dir.join("f.txt").canonicalize().unwrap())]);
+
+    //移除较具体的监听路径后，其事件通知包含该路径的监听路径
+    monitor.remove_monitor(Atom::from(sub.to_str().unwrap())).unwrap();
+    events.lock().unwrap().clear();
+    fs::write(sub.join("3.txt"), b"0").unwrap();
+    thread::sleep(Duration::from_millis(300));
+
+    let roots: Vec<_> = events.lock().unwrap().iter().map(|e| (e.root.clone(), e.path.clone())).collect();
+    assert_eq!(roots, vec![(dir.clone(), sub.join("3.txt"))]);
+
+    monitor.stop().unwrap();
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_fs_monitor_rename_window() {
+    let dir = fresh_dir("pi_file_test_rename_window");
+    let dir_a = dir.join("a");
+    let dir_b = dir.join("b");
+    fs::create_dir_all(&dir_a).unwrap();
+    fs::create_dir_all(&dir_b).unwrap();
+    fs::write(dir_a.join("x.txt"), b"0").unwrap();
+
+    let (events, listener) = recording_listener();
+    let mut monitor = FSMonitor::new(FSMonitorOptions::Dirs(vec![(Atom::from(dir_a.to_str().unwrap()), true, 100),
+                                                                 (Atom::from(dir_b.to_str().unwrap()), true, 100)]), listener);
+    monitor.set_rename_window(500).unwrap();
+    monitor.run().unwrap();
+    thread::sleep(Duration::from_millis(200));
+
+    //在不同监听路径之间移动，合并为一次改名
+    fs::rename(dir_a.join("x.txt"), dir_b.join("x.txt")).unwrap();
+    thread::sleep(Duration::from_millis(400));
+    fs::remove_file(dir_b.join("x.txt")).unwrap();
+    thread::sleep(Duration::from_millis(1000));
+
+    let kinds: Vec<_> = events.lock().unwrap().iter().map(|e| e.kind.clone()).collect();
+    assert_eq!(kinds, vec![FSEventKind::Renamed { from: dir_a.join("x.txt"), to: dir_b.join("x.txt") }, FSEventKind::Removed]);
+
+    monitor.stop().unwrap();
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_fs_monitor_watched() {
+    let dir = fresh_dir("pi_file_test_watched");
+    let dir_a = dir.join("a");
+    let dir_b = dir.join("b");
+    fs::create_dir_all(&dir_a).unwrap();
+    fs::create_dir_all(&dir_b).unwrap();
+
+    let events: Arc<Mutex<Vec<FSEvent>>> = Arc::new(Mutex::new(Vec::new()));
+    let events_copy = events.clone();
+    let listener = FSListener(Arc::new(move |event| {
+        //模拟处理缓慢的监听者，使后续事件在队列中等待通知
+        thread::sleep(Duration::from_millis(500));
+        events_copy.lock().unwrap().push(event);
+    }));
+    let mut monitor = FSMonitor::new(FSMonitorOptions::Dir(Atom::from(dir_a.to_str().unwrap()), true, 100), listener);
+    monitor.run().unwrap();
+    assert_eq!(monitor.watched(), vec![dir_a.clone()]);
+
+    //运行时增加监听
+    monitor.add_monitor(FSMonitorOptions::Dir(Atom::from(dir_b.to_str().unwrap()), true, 100)).unwrap();
+    assert_eq!(monitor.watched(), vec![dir_a.clone(), dir_b.clone()]);
+    thread::sleep(Duration::from_millis(200));
+
+    //运行时移除监听，移除前已产生但还未通知的事件也不再通知
+    fs::write(dir_b.join("0.txt"), b"0").unwrap();
+    thread::sleep(Duration::from_millis(150));
+    fs::write(dir_a.join("1.txt"), b"0").unwrap();
+    thread::sleep(Duration::from_millis(200));
+    monitor.remove_monitor(Atom::from(dir_a.to_str().unwrap())).unwrap();
+    assert_eq!(monitor.watched(), vec![dir_b.clone()]);
+    fs::write(dir_a.join("2.txt"), b"0").unwrap();
+    thread::sleep(Duration::from_millis(800));
+
+    let paths: Vec<_> = events.lock().unwrap().iter().map(|e| e.path.clone()).collect();
+    assert_eq!(paths, vec![dir_b.join("0.txt")]);
+
+    monitor.stop().unwrap();
+    assert!(monitor.watched().is_empty());
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_fs_monitor_debounce() {
+    let dir = fresh_dir("pi_file_test_debounce");
+    fs::write(dir.join("a.txt"), b"0").unwrap();
+
+    let (events, listener) = recording_listener();
+    let mut monitor = FSMonitor::new(FSMonitorOptions::Dir(Atom::from(dir.to_str().unwrap()), true, 300), listener);
+    monitor.run().unwrap();
+    thread::sleep(Duration::from_millis(200));
+
+    //缓冲时间内多次修改同一路径，只通知一次修改
+    for i in 0..5 {
+        fs::write(dir.join("a.txt"), format!("{}", i)).unwrap();
+        thread::sleep(Duration::from_millis(20));
+    }
+    thread::sleep(Duration::from_millis(800));
+    let kinds: Vec<_> = events.lock().unwrap().drain(..).map(|e| (e.path, e.kind)).collect();
+    assert_eq!(kinds, vec![(dir.join("a.txt"), FSEventKind::Modified)]);
+
+    //缓冲时间内创建后修改，只通知一次创建
+    fs::write(dir.join("b.txt"), b"0").unwrap();
+    thread::sleep(Duration::from_millis(20));
+    fs::write(dir.join("b.txt"), b"1").unwrap();
+    thread::sleep(Duration::from_millis(800));
+    let kinds: Vec<_> = events.lock().unwrap().drain(..).map(|e| (e.path, e.kind)).collect();
+    assert_eq!(kinds, vec![(dir.join("b.txt"), FSEventKind::Created)]);
+
+    monitor.stop().unwrap();
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_fs_monitor_time() {
+    let dir = fresh_dir("pi_file_test_time");
+
+    let events: Arc<Mutex<Vec<(FSEvent, SystemTime)>>> = Arc::new(Mutex::new(Vec::new()));
+    let events_copy = events.clone();
+    let listener = FSListener(Arc::new(move |event| {
+        events_copy.lock().unwrap().push((event, SystemTime::now()));
+    }));
+    let mut monitor = FSMonitor::new(FSMonitorOptions::Dir(Atom::from(dir.to_str().unwrap()), true, 500), listener);
+    monitor.run().unwrap();
+    thread::sleep(Duration::from_millis(200));
+
+    let before = SystemTime::now();
+    fs::write(dir.join("a.txt"), b"0").unwrap();
+    let after = SystemTime::now();
+    thread::sleep(Duration::from_millis(1000));
+
+    //事件时间为发生事件的时间，而不是通知监听者的时间
+    let events = events.lock().unwrap();
+    assert_eq!(events.len(), 1);
+    let (event, notified) = &events[0];
+    assert!(event.time >= before);
+    assert!(event.time <= after + Duration::from_millis(200));
+    assert!(*notified >= event.time + Duration::from_millis(400));
+
+    monitor.stop().unwrap();
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_fs_monitor_control() {
+    let dir = fresh_dir("pi_file_test_control");
+
+    let events: Arc<Mutex<Vec<FSEvent>>> = Arc::new(Mutex::new(Vec::new()));
+    let events_copy = events.clone();
+    let listener = FSListenerControl(Arc::new(move |event: FSEvent| {
+        let is_stop = event.path.ends_with("stop");
+        events_copy.lock().unwrap().push(event);
+        if is_stop {
+            ControlFlow::Break(())
+        } else {
+            ControlFlow::Continue(())
         }
+    }));
+    let mut monitor = FSMonitor::with_control(FSMonitorOptions::Dir(Atom::from(dir.to_str().unwrap()), true, 100), listener);
+    monitor.run().unwrap();
+    thread::sleep(Duration::from_millis(200));
+
+    //监听者请求关闭后，不再通知事件
+    fs::write(dir.join("a.txt"), b"0").unwrap();
+    thread::sleep(Duration::from_millis(300));
+    fs::write(dir.join("stop"), b"0").unwrap();
+    thread::sleep(Duration::from_millis(300));
+    fs::write(dir.join("b.txt"), b"0").unwrap();
+    thread::sleep(Duration::from_millis(300));
+
+    let paths: Vec<_> = events.lock().unwrap().drain(..).map(|e| e.path).collect();
+    assert_eq!(paths, vec![dir.join("a.txt"), dir.join("stop")]);
+
+    //监听者请求关闭后，不再运行且移除所有路径的监听，可以再次运行
+    assert!(!monitor.is_running());
+    assert!(monitor.watched().is_empty());
+    assert!(monitor.pause(100).is_err());
+    monitor.run().unwrap();
+    assert!(monitor.is_running());
+    thread::sleep(Duration::from_millis(200));
+    fs::write(dir.join("c.txt"), b"0").unwrap();
+    thread::sleep(Duration::from_millis(300));
+
+    let paths: Vec<_> = events.lock().unwrap().drain(..).map(|e| e.path).collect();
+    assert_eq!(paths, vec![dir.join("c.txt")]);
+
+    monitor.stop().unwrap();
+    assert!(!monitor.is_running());
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_fs_monitor_symlinks() {
+    use std::os::unix::fs::symlink;
+
+    let dir = fresh_dir("pi_file_test_symlinks");
+    let root = dir.join("root");
+    let outside = dir.join("outside");
+    fs::create_dir_all(root.join("real")).unwrap();
+    fs::create_dir_all(&outside).unwrap();
+    fs::write(outside.join("x.txt"), b"0").unwrap();
+    symlink(&outside, root.join("link")).unwrap();
+    symlink(&root, root.join("loop")).unwrap();
+
+    let (events, listener) = recording_listener();
+    let mut monitor = FSMonitor::new(FSMonitorOptions::Dir(Atom::from(root.to_str().unwrap()), true, 100), listener.clone());
+    monitor.set_emit_existing(true).unwrap();
+    monitor.run().unwrap();
+    thread::sleep(Duration::from_millis(200));
+
+    //默认不跟随符号链接的目录，只通知符号链接本身
+    fs::write(outside.join("x.txt"), b"1").unwrap();
+    fs::write(root.join("real").join("y.txt"), b"0").unwrap();
+    thread::sleep(Duration::from_millis(400));
+    monitor.stop().unwrap();
+
+    let paths: Vec<_> = events.lock().unwrap().drain(..).map(|e| e.path).collect();
+    assert_eq!(paths, vec![root.join("link"), root.join("loop"), root.join("real"), root.join("real").join("y.txt")]);
+
+    //跟随符号链接的目录时，初始遍历不会因为循环而重复进入同一个目录
+    let mut monitor = FSMonitor::new(FSMonitorOptions::Dir(Atom::from(root.to_str().unwrap()), true, 100), listener);
+    monitor.set_emit_existing(true).unwrap();
+    monitor.set_follow_symlinks(true).unwrap();
+    monitor.run().unwrap();
+    thread::sleep(Duration::from_millis(400));
+    monitor.stop().unwrap();
+
+    let paths: Vec<_> = events.lock().unwrap().drain(..).map(|e| e.path).collect();
+    assert_eq!(paths, vec![root.join("link"), root.join("link").join("x.txt"), root.join("loop"),
+                           root.join("real"), root.join("real").join("y.txt")]);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_fs_monitor_symlink_alias() {
+    use std::os::unix::fs::symlink;
+
+    let root = fresh_dir("pi_file_test_symlink_alias");
+    fs::create_dir_all(root.join("real")).unwrap();
+    for i in 0..4 {
+        symlink(root.join("real"), root.join(format!("alias{}", i))).unwrap();
     }
+
+    let (events, listener) = recording_listener();
+    let mut monitor = FSMonitor::new(FSMonitorOptions::Dir(Atom::from(root.to_str().unwrap()), true, 100), listener);
+    monitor.run().unwrap();
+    thread::sleep(Duration::from_millis(200));
+
+    //符号链接指向监听路径下的目录时，仍然通知该目录的事件，且为实际路径
+    fs::write(root.join("real").join("y.txt"), b"0").unwrap();
+    thread::sleep(Duration::from_millis(400));
+
+    //新建的目录也会监听，开始监听前已在其中创建的文件和目录作为创建事件通知
+    fs::create_dir_all(root.join("new").join("deep")).unwrap();
+    fs::write(root.join("new").join("deep").join("w.txt"), b"0").unwrap();
+    thread::sleep(Duration::from_millis(400));
+    fs::write(root.join("new").join("deep").join("z.txt"), b"0").unwrap();
+    thread::sleep(Duration::from_millis(400));
+
+    let paths: Vec<_> = events.lock().unwrap().iter().map(|e| e.path.clone()).collect();
+    assert_eq!(paths, vec![root.join("real").join("y.txt"),
+                           root.join("new"),
+                           root.join("new").join("deep"),
+                           root.join("new").join("deep").join("w.txt"),
+                           root.join("new").join("deep").join("z.txt")]);
+
+    monitor.stop().unwrap();
+    let _ = fs::remove_dir_all(&root);
 }